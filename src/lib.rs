@@ -36,10 +36,16 @@ impl Colour {
     fn paint<'l>(&self, level: &'l str) -> &'l str {
         level
     }
+
+    fn bold(&self) -> &Self {
+        self
+    }
 }
 
 
+use std::fmt::Display;
 use std::io::{self, Write};
+use std::sync::{Mutex, OnceLock};
 
 /// Prepend file and line info into a given message.
 ///
@@ -73,39 +79,429 @@ macro_rules! expect {
 /// useful for debugging.
 #[macro_export]
 macro_rules! internal {
-    ($message:expr) => ($crate::internal(flm!($message)));
+    ($message:expr) => ($crate::internal_at(file!(), line!(), $message));
+
+    ($format:expr, $( $val:expr ),+) => (
+        $crate::internal_at(file!(), line!(), &format!($format, $( $val ),+))
+    );
+}
+
+/// Unwrap a value, terminating with a fatal error message on failure.
+///
+/// Unlike `.expect()`, the failure path does not panic: it prints a clean `fatal` diagnostic with
+/// file and line context and exits with a non-zero status, so end users never see a backtrace.
+#[macro_export]
+macro_rules! unwrap_message {
+    ($value:expr, $message:expr) => ($crate::UnwrapExt::expect_fatal($value, flm!($message)));
+}
+
+/// Unwrap a value, terminating with a `format!`-style fatal error message on failure.
+///
+/// See [`unwrap_message!`](macro.unwrap_message.html) for the single-message form.
+#[macro_export]
+macro_rules! unwrap_format {
+    ($value:expr, $format:expr, $( $val:expr ),+) => (
+        $crate::UnwrapExt::expect_fatal($value, &flm!($format, $( $val ),+))
+    );
+}
+
+/// Display an error as a rustc/cargo-style diagnostic.
+///
+/// A source location and error code may be attached with the `at:`/`line:`/`col:` and `code:`
+/// prefixes. The location is given as all three of `at:`, `line:` and `col:` together, and `code:`
+/// comes after it when both are present; either the location or the code may be omitted. The bare
+/// `error!("msg")` form prints a plain error through [`error`](fn.error.html).
+#[macro_export]
+macro_rules! error {
+    (at: $path:expr, line: $line:expr, col: $col:expr, code: $code:expr, $format:expr $(, $val:expr)*) => (
+        $crate::print_diagnostic(
+            $crate::Level::Error,
+            Some($code),
+            Some(($path, $line, $col)),
+            &format!($format $(, $val)*),
+        )
+    );
+
+    (at: $path:expr, line: $line:expr, col: $col:expr, $format:expr $(, $val:expr)*) => (
+        $crate::print_diagnostic(
+            $crate::Level::Error,
+            None,
+            Some(($path, $line, $col)),
+            &format!($format $(, $val)*),
+        )
+    );
+
+    (code: $code:expr, $format:expr $(, $val:expr)*) => (
+        $crate::print_diagnostic(
+            $crate::Level::Error,
+            Some($code),
+            None,
+            &format!($format $(, $val)*),
+        )
+    );
+
+    ($format:expr, $( $val:expr ),+) => ($crate::error(&format!($format, $( $val ),+)));
+
+    ($message:expr) => ($crate::error($message));
+}
+
+/// Print a warning message, optionally with `format!`-style arguments.
+#[macro_export]
+macro_rules! warn {
+    ($format:expr, $( $val:expr ),+) => ($crate::warn(&format!($format, $( $val ),+)));
+
+    ($message:expr) => ($crate::warn($message));
+}
+
+/// Print some non-erroneous information, optionally with `format!`-style arguments.
+#[macro_export]
+macro_rules! info {
+    ($format:expr, $( $val:expr ),+) => ($crate::info(&format!($format, $( $val ),+)));
+
+    ($message:expr) => ($crate::info($message));
+}
+
+/// Print a fatal error message, optionally with `format!`-style arguments, then terminate.
+#[macro_export]
+macro_rules! fatal {
+    (code: $code:expr, $format:expr $(, $val:expr)*) => (
+        $crate::fatal_code($code, &format!($format $(, $val)*))
+    );
+
+    ($format:expr, $( $val:expr ),+) => ($crate::fatal(&format!($format, $( $val ),+)));
+
+    ($message:expr) => ($crate::fatal($message));
+}
+
+/// The severity of a logged message.
+///
+/// Levels carry an explicit numeric ordering so that a single threshold can drive runtime
+/// filtering: `Info < Warning < Error < Fatal < Internal`. A [`Logger`](struct.Logger.html)
+/// discards any message whose level is below its configured minimum.
+///
+/// `Internal` sits at the top on purpose: a raised threshold (a `-q` flag) should quieten routine
+/// chatter, never the "bugs or failed invariants" diagnostics, so those can't be silenced away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Info = 0,
+    Warning = 1,
+    Error = 2,
+    Fatal = 3,
+    Internal = 4,
+}
+
+impl Level {
+    fn colour(&self) -> Colour {
+        match *self {
+            Level::Internal | Level::Fatal | Level::Error => Colour::Red,
+            Level::Warning => Colour::Yellow,
+            Level::Info => Colour::Purple,
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match *self {
+            Level::Internal => "internal",
+            Level::Fatal => "fatal",
+            Level::Error => "error",
+            Level::Warning => "warning",
+            Level::Info => "info",
+        }
+    }
+}
+
+/// How a [`Logger`](struct.Logger.html) renders each message.
+///
+/// The default is `Human`, the familiar `program:level: message` line. `Json` emits one JSON
+/// object per line so that editors, CI and other tooling can ingest diagnostics reliably, in the
+/// spirit of a larger CLI's `--error-format=json`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Human,
+    Json,
+}
+
+/// A configurable sink for diagnostic messages.
+///
+/// A `Logger` bundles a minimum [`Level`](enum.Level.html) threshold, an output target, a colour
+/// toggle and an [`OutputFormat`](enum.OutputFormat.html). The free functions
+/// ([`internal`](fn.internal.html), [`error`](fn.error.html), …) delegate to a process-global
+/// default logger, so most programs never construct one directly; doing so is useful for mapping a
+/// `-v`/`-q` flag onto a threshold or for capturing output in tests.
+pub struct Logger {
+    level: Level,
+    target: Box<dyn Write + Send>,
+    colour: bool,
+    format: OutputFormat,
+}
+
+impl Logger {
+    /// Create a logger writing to stderr, showing every level, with colour following the `colour`
+    /// feature.
+    pub fn new() -> Logger {
+        Logger {
+            level: Level::Info,
+            target: Box::new(io::stderr()),
+            colour: cfg!(feature = "colour"),
+            format: OutputFormat::Human,
+        }
+    }
+
+    /// Set the minimum level below which messages are discarded.
+    pub fn level(&mut self, level: Level) -> &mut Logger {
+        self.level = level;
+        self
+    }
+
+    /// Set the output target.
+    pub fn target(&mut self, target: Box<dyn Write + Send>) -> &mut Logger {
+        self.target = target;
+        self
+    }
+
+    /// Toggle coloured output.
+    pub fn colour(&mut self, colour: bool) -> &mut Logger {
+        self.colour = colour;
+        self
+    }
+
+    /// Set the output format.
+    pub fn format(&mut self, format: OutputFormat) -> &mut Logger {
+        self.format = format;
+        self
+    }
+
+    /// Flush the underlying output target.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.target.flush()
+    }
+
+    /// Write a message at the given level, discarding it when below the threshold.
+    pub fn log(&mut self, level: Level, message: &str) -> io::Result<()> {
+        if level < self.level {
+            return Ok(());
+        }
+
+        let program = try!(std::env::current_exe());
+        let program = program.file_name().and_then(|n| n.to_str());
+
+        match self.format {
+            OutputFormat::Human => self.emit_human(program, level, message),
+            OutputFormat::Json => self.emit_json(program, level, None, None, message),
+        }
+    }
+
+    /// Write a rustc/cargo-style diagnostic with an optional error code and source location.
+    pub fn diagnostic(
+        &mut self,
+        level: Level,
+        code: Option<&str>,
+        loc: Option<(&str, u32, u32)>,
+        message: &str,
+    ) -> io::Result<()> {
+        if level < self.level {
+            return Ok(());
+        }
+
+        let program = try!(std::env::current_exe());
+        let program = program.file_name().and_then(|n| n.to_str());
 
-    ($format:expr, $( $val:expr ),+) => ($crate::internal(&flm!($format, $( $val ),+)));
+        match self.format {
+            OutputFormat::Human => self.diagnostic_human(level, code, loc, message),
+            OutputFormat::Json => {
+                let loc = loc.map(|(file, line, col)| (file, line, Some(col)));
+                self.emit_json(program, level, code, loc, message)
+            }
+        }
+    }
+
+    /// Write an internal error, carrying its file and line as structured data.
+    ///
+    /// In human mode the location is prepended to the message (as [`flm!`](macro.flm.html) used to
+    /// do by hand); in JSON mode it becomes `file`/`line` fields, leaving `message` clean for
+    /// machine consumers.
+    pub fn log_internal(&mut self, file: &str, line: u32, message: &str) -> io::Result<()> {
+        if Level::Internal < self.level {
+            return Ok(());
+        }
+
+        let program = try!(std::env::current_exe());
+        let program = program.file_name().and_then(|n| n.to_str());
+
+        match self.format {
+            OutputFormat::Human => {
+                let prefixed = format!("{}:{}: {}", file, line, message);
+                self.emit_human(program, Level::Internal, &prefixed)
+            }
+            OutputFormat::Json => {
+                self.emit_json(program, Level::Internal, None, Some((file, line, None)), message)
+            }
+        }
+    }
+
+    fn diagnostic_human(
+        &mut self,
+        level: Level,
+        code: Option<&str>,
+        loc: Option<(&str, u32, u32)>,
+        message: &str,
+    ) -> io::Result<()> {
+        let label = level.label();
+        let header = match code {
+            Some(code) => format!("{}[{}]", label, code),
+            None => label.to_string(),
+        };
+
+        if self.colour {
+            try!(writeln!(
+                self.target,
+                "{}: {}",
+                level.colour().bold().paint(header.as_str()),
+                message,
+            ));
+            if let Some((file, line, col)) = loc {
+                try!(writeln!(
+                    self.target,
+                    "  {} {}:{}:{}",
+                    Colour::Blue.bold().paint("-->"),
+                    file,
+                    line,
+                    col,
+                ));
+            }
+            Ok(())
+        } else {
+            try!(writeln!(self.target, "{}: {}", header, message));
+            if let Some((file, line, col)) = loc {
+                try!(writeln!(self.target, "  --> {}:{}:{}", file, line, col));
+            }
+            Ok(())
+        }
+    }
+
+    fn emit_human(&mut self, program: Option<&str>, level: Level, message: &str) -> io::Result<()> {
+        let label = level.label();
+
+        if self.colour {
+            let colour = level.colour();
+            match program {
+                Some(name) => writeln!(
+                    self.target,
+                    "{}:{}: {}",
+                    Colour::Blue.paint(name),
+                    colour.paint(label),
+                    message,
+                ),
+                None => writeln!(self.target, "{}: {}", colour.paint(label), message),
+            }
+        } else {
+            match program {
+                Some(name) => writeln!(self.target, "{}:{}: {}", name, label, message),
+                None => writeln!(self.target, "{}: {}", label, message),
+            }
+        }
+    }
+
+    fn emit_json(
+        &mut self,
+        program: Option<&str>,
+        level: Level,
+        code: Option<&str>,
+        loc: Option<(&str, u32, Option<u32>)>,
+        message: &str,
+    ) -> io::Result<()> {
+        let mut object = format!("{{\"severity\":\"{}\"", level.label());
+        object.push_str(&format!(",\"message\":\"{}\"", json_escape(message)));
+        if let Some(name) = program {
+            object.push_str(&format!(",\"program\":\"{}\"", json_escape(name)));
+        }
+        if let Some(code) = code {
+            object.push_str(&format!(",\"code\":\"{}\"", json_escape(code)));
+        }
+        if let Some((file, line, col)) = loc {
+            object.push_str(&format!(",\"file\":\"{}\"", json_escape(file)));
+            object.push_str(&format!(",\"line\":{}", line));
+            if let Some(col) = col {
+                object.push_str(&format!(",\"column\":{}", col));
+            }
+        }
+        object.push('}');
+        writeln!(self.target, "{}", object)
+    }
 }
 
-fn print(colour: Colour, level: &str, message: &str) -> io::Result<()> {
-    let program = try!(std::env::current_exe());
-    let program = program.file_name().and_then(|n| n.to_str());
-    match program {
-        Some(name) => writeln!(
-            io::stderr(),
-            "{}:{}: {}",
-            Colour::Blue.paint(name),
-            colour.paint(level),
-            message,
-        ),
-        None => writeln!(io::stderr(), "{}: {}", colour.paint(level), message),
+/// Escape a string so it can be embedded in a JSON string literal.
+fn json_escape(input: &str) -> String {
+    let mut escaped = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
     }
+    escaped
+}
+
+impl Default for Logger {
+    fn default() -> Logger {
+        Logger::new()
+    }
+}
+
+fn default_logger() -> &'static Mutex<Logger> {
+    static LOGGER: OnceLock<Mutex<Logger>> = OnceLock::new();
+    LOGGER.get_or_init(|| Mutex::new(Logger::new()))
+}
+
+/// The process-global default logger that the free functions delegate to.
+///
+/// Lock it to adjust the threshold, target or colour setting shared by every call site, e.g.
+/// `userror::logger().lock().unwrap().level(Level::Warning);`.
+pub fn logger() -> &'static Mutex<Logger> {
+    default_logger()
 }
 
 /// Print an internal error message.
 ///
 /// Internal errors are bugs or failed invariants in your program. They are not necessarily fatal.
 pub fn internal(message: &str) -> io::Result<()> {
-    print(Colour::Red, "internal", message)
+    default_logger().lock().unwrap().log(Level::Internal, message)
+}
+
+/// Print an internal error message tagged with its source file and line.
+///
+/// This is the target of the [`internal!`](macro.internal.html) macro: the location is rendered
+/// inline for humans but surfaced as structured `file`/`line` fields in JSON mode.
+pub fn internal_at(file: &str, line: u32, message: &str) -> io::Result<()> {
+    default_logger().lock().unwrap().log_internal(file, line, message)
 }
 
-/// Print a fatal error message and panic.
+/// Print a fatal error message and terminate with exit status `1`.
 ///
 /// Fatal errors are errors which can not be recovered from, such as failing to receive user input.
+/// This is a convenience wrapper around [`fatal_code`](fn.fatal_code.html).
 pub fn fatal(message: &str) -> ! {
-    print(Colour::Red, "fatal", message).expect("failed to write error message");
-    panic!("fatal error occurred");
+    fatal_code(1, message)
+}
+
+/// Print a fatal error message and terminate with the given exit status.
+///
+/// The message is written through the default logger and the logger's target (stderr by default)
+/// is flushed before exiting, so shell scripts and CI can branch on `code` without the diagnostic
+/// being left in a buffer. Like any other message it is discarded when the logger's threshold is
+/// above `Fatal`.
+pub fn fatal_code(code: i32, message: &str) -> ! {
+    {
+        let mut logger = default_logger().lock().unwrap();
+        let _ = logger.log(Level::Fatal, message);
+        let _ = logger.flush();
+    }
+    std::process::exit(code);
 }
 
 /// Print an error message.
@@ -113,7 +509,7 @@ pub fn fatal(message: &str) -> ! {
 /// Errors are recoverable but prevent the program from working properly or in it's entirety, such
 /// as failing to open an output file and instead printing results to screen.
 pub fn error(message: &str) -> io::Result<()> {
-    print(Colour::Red, "error", message)
+    default_logger().lock().unwrap().log(Level::Error, message)
 }
 
 /// Print a warning message.
@@ -121,10 +517,179 @@ pub fn error(message: &str) -> io::Result<()> {
 /// Warnings lead to sub-optimal, but not strictly incorrect, behaviour. An example would be
 /// failing to load a custom stylesheet and instead using a default one.
 pub fn warn(message: &str) -> io::Result<()> {
-    print(Colour::Yellow, "warning", message)
+    default_logger().lock().unwrap().log(Level::Warning, message)
 }
 
 /// Print some non-erroneous information.
 pub fn info(message: &str) -> io::Result<()> {
-    print(Colour::Purple, "info", message)
+    default_logger().lock().unwrap().log(Level::Info, message)
+}
+
+/// Print a rustc/cargo-style diagnostic through the default logger.
+///
+/// With `code` and `loc` supplied the human output gains a bold coloured `error[E1234]: message`
+/// header followed by an indented `--> file:line:col` line; with both `None` it collapses to the
+/// header on its own. The macros ([`error!`](macro.error.html)) are the usual entry point.
+pub fn print_diagnostic(
+    level: Level,
+    code: Option<&str>,
+    loc: Option<(&str, u32, u32)>,
+    message: &str,
+) -> io::Result<()> {
+    default_logger().lock().unwrap().diagnostic(level, code, loc, message)
+}
+
+/// Unwrap a `Result` or `Option`, printing a fatal error and exiting on failure.
+///
+/// These methods mirror the standard `expect`/`unwrap` but, instead of panicking, route the
+/// failure through the same `fatal` machinery as [`fatal`](fn.fatal.html) and then terminate the
+/// process with status `1`. This keeps the error output user-facing rather than leaving a
+/// backtrace behind.
+pub trait UnwrapExt<T> {
+    /// Unwrap the value, or print `msg` (alongside any underlying error) and exit.
+    fn expect_fatal(self, msg: &str) -> T;
+
+    /// Unwrap the value, or print the underlying error and exit.
+    fn unwrap_fatal(self) -> T;
+}
+
+fn die(message: &str) -> ! {
+    fatal_code(1, message)
+}
+
+impl<T, E: Display> UnwrapExt<T> for Result<T, E> {
+    fn expect_fatal(self, msg: &str) -> T {
+        match self {
+            Ok(value) => value,
+            Err(err) => die(&format!("{}: {}", msg, err)),
+        }
+    }
+
+    fn unwrap_fatal(self) -> T {
+        match self {
+            Ok(value) => value,
+            Err(err) => die(&format!("{}", err)),
+        }
+    }
+}
+
+impl<T> UnwrapExt<T> for Option<T> {
+    fn expect_fatal(self, msg: &str) -> T {
+        match self {
+            Some(value) => value,
+            None => die(msg),
+        }
+    }
+
+    fn unwrap_fatal(self) -> T {
+        match self {
+            Some(value) => value,
+            None => die("called `unwrap_fatal()` on a `None` value"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+
+    /// A `Write` target that keeps a shared handle to its buffer so tests can read it back.
+    #[derive(Clone)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.lock().unwrap().flush()
+        }
+    }
+
+    /// A logger writing into an in-memory buffer with colour off, plus a handle to that buffer.
+    fn capture() -> (Logger, Arc<Mutex<Vec<u8>>>) {
+        let shared = Arc::new(Mutex::new(Vec::new()));
+        let mut logger = Logger::new();
+        logger.target(Box::new(SharedBuf(shared.clone()))).colour(false);
+        (logger, shared)
+    }
+
+    fn captured(buffer: &Arc<Mutex<Vec<u8>>>) -> String {
+        String::from_utf8(buffer.lock().unwrap().clone()).unwrap()
+    }
+
+    #[test]
+    fn json_escape_escapes_control_and_quotes() {
+        assert_eq!(json_escape("a\"b\\c\n"), "a\\\"b\\\\c\\n");
+        assert_eq!(json_escape("tab\there"), "tab\\there");
+        assert_eq!(json_escape("bell\u{7}"), "bell\\u0007");
+    }
+
+    #[test]
+    fn log_below_threshold_writes_nothing() {
+        let (mut logger, buffer) = capture();
+        logger.level(Level::Error);
+        logger.log(Level::Info, "chatter").unwrap();
+        assert!(captured(&buffer).is_empty());
+    }
+
+    #[test]
+    fn internal_is_never_filtered_by_a_raised_threshold() {
+        let (mut logger, buffer) = capture();
+        logger.level(Level::Fatal);
+        logger.log(Level::Internal, "invariant broke").unwrap();
+        assert!(captured(&buffer).contains("invariant broke"));
+    }
+
+    #[test]
+    fn human_log_carries_level_and_message() {
+        let (mut logger, buffer) = capture();
+        logger.log(Level::Warning, "careful now").unwrap();
+        let out = captured(&buffer);
+        assert!(out.contains("warning: careful now"));
+    }
+
+    #[test]
+    fn json_log_emits_severity_and_message() {
+        let (mut logger, buffer) = capture();
+        logger.format(OutputFormat::Json);
+        logger.log(Level::Error, "boom").unwrap();
+        let out = captured(&buffer);
+        assert!(out.contains("\"severity\":\"error\""));
+        assert!(out.contains("\"message\":\"boom\""));
+    }
+
+    #[test]
+    fn internal_json_has_structured_location_and_clean_message() {
+        let (mut logger, buffer) = capture();
+        logger.format(OutputFormat::Json);
+        logger.log_internal("examples/demo.rs", 6, "disk invariant broke").unwrap();
+        let out = captured(&buffer);
+        assert!(out.contains("\"file\":\"examples/demo.rs\""));
+        assert!(out.contains("\"line\":6"));
+        assert!(out.contains("\"message\":\"disk invariant broke\""));
+        assert!(!out.contains("examples/demo.rs:6: disk invariant broke"));
+    }
+
+    #[test]
+    fn internal_human_prepends_location() {
+        let (mut logger, buffer) = capture();
+        logger.log_internal("examples/demo.rs", 6, "disk invariant broke").unwrap();
+        let out = captured(&buffer);
+        assert!(out.contains("examples/demo.rs:6: disk invariant broke"));
+    }
+
+    #[test]
+    fn diagnostic_human_renders_code_and_location() {
+        let (mut logger, buffer) = capture();
+        logger
+            .diagnostic(Level::Error, Some("E1234"), Some(("src/parse.rs", 4, 2)), "unexpected token")
+            .unwrap();
+        let out = captured(&buffer);
+        assert!(out.contains("error[E1234]: unexpected token"));
+        assert!(out.contains("--> src/parse.rs:4:2"));
+    }
 }